@@ -0,0 +1,253 @@
+use std::{
+    fmt,
+    io::{self, Error, ErrorKind},
+};
+
+/// Status line information carried on the `NATS/1.0` version line of a
+/// header block, e.g. `NATS/1.0 503 No Responders`.
+#[derive(Debug, Clone)]
+pub(crate) struct HeaderStatus {
+    pub(crate) code: u16,
+    pub(crate) description: String,
+}
+
+/// Returned by [`HeaderMap::insert`] when a name or value can't be
+/// represented in the `NATS/1.0` wire format: a name containing `:` would
+/// round-trip to the wrong `(name, value)` split on parse, and a `\r` or
+/// `\n` in either a name or a value would let a caller inject extra,
+/// fabricated header lines into the block sent to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidHeader(String);
+
+impl fmt::Display for InvalidHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidHeader {}
+
+/// An ordered multimap of header name to one or more values, mirroring the
+/// `HeaderMap` abstraction exposed by the async client. Headers travel over
+/// the wire as part of the `HPUB`/`HMSG` protocol operations: a version
+/// line (`NATS/1.0`), zero or more `Key: Value` lines, and a terminating
+/// blank line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, Vec<String>)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    /// Appends `value` to the list of values already registered under
+    /// `name`, preserving insertion order. Header names are matched
+    /// case-insensitively, per the HTTP-inspired wire format.
+    ///
+    /// Returns an error instead of inserting if `name` contains `:` (which
+    /// would be ambiguous with the `Key: Value` delimiter on parse) or if
+    /// either `name` or `value` contains a CR or LF (which would let the
+    /// header block smuggle in extra, fabricated lines once rendered).
+    pub fn insert<K: Into<String>, V: Into<String>>(
+        &mut self,
+        name: K,
+        value: V,
+    ) -> Result<(), InvalidHeader> {
+        let name = name.into();
+        let value = value.into();
+
+        if name.is_empty() || name.bytes().any(|b| b == b':' || b == b'\r' || b == b'\n') {
+            return Err(InvalidHeader(format!("invalid header name: {:?}", name)));
+        }
+        if value.bytes().any(|b| b == b'\r' || b == b'\n') {
+            return Err(InvalidHeader(format!("invalid header value: {:?}", value)));
+        }
+
+        match self
+            .entries
+            .iter_mut()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(&name))
+        {
+            Some((_, values)) => values.push(value),
+            None => self.entries.push((name, vec![value])),
+        }
+        Ok(())
+    }
+
+    /// Returns the first value registered under `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// Returns all values registered under `name`, in insertion order.
+    pub fn get_all(&self, name: &str) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .filter(move |(existing, _)| existing.eq_ignore_ascii_case(name))
+            .flat_map(|(_, values)| values.iter().map(String::as_str))
+    }
+
+    /// Iterates over every `(name, value)` pair, in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .flat_map(|(name, values)| values.iter().map(move |value| (name.as_str(), value.as_str())))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Renders this map as the header block that follows an `HPUB`/`HMSG`
+    /// control line: the `NATS/1.0` version line, one `Key: Value` line per
+    /// entry, and a terminating blank line.
+    pub(crate) fn render(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(b"NATS/1.0\r\n");
+        for (name, value) in self.iter() {
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(b": ");
+            buf.extend_from_slice(value.as_bytes());
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(b"\r\n");
+        buf
+    }
+
+    /// Parses a header block as delivered inline with an `HMSG` frame,
+    /// returning the parsed headers along with the status line, if one was
+    /// present (used for no-responders / `503` semantics).
+    pub(crate) fn parse(block: &[u8]) -> io::Result<(HeaderMap, Option<HeaderStatus>)> {
+        let text = std::str::from_utf8(block)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid header block: {}", e)))?;
+
+        // Split on the literal "\r\n" delimiter, then make sure no stray CR
+        // or LF survived inside any one line. A lone '\n' (without a
+        // preceding '\r') stays inside a single `split` element rather than
+        // acting as a delimiter, so without this check a value like
+        // "a\nInjected: yes" would sail through to `insert` and its own
+        // CR/LF rejection would turn into a `.expect()` panic below instead
+        // of the `io::Error` a malformed wire frame deserves.
+        for line in text.split("\r\n") {
+            if line.bytes().any(|b| b == b'\r' || b == b'\n') {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "header block contains a stray CR or LF outside a line terminator",
+                ));
+            }
+        }
+
+        let mut lines = text.split("\r\n");
+        let version_line = lines.next().unwrap_or_default();
+        if !version_line.starts_with("NATS/1.0") {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("malformed header version line: {:?}", version_line),
+            ));
+        }
+
+        let status_str = version_line["NATS/1.0".len()..].trim();
+        let status = if status_str.is_empty() {
+            None
+        } else {
+            let mut parts = status_str.splitn(2, ' ');
+            let code = parts
+                .next()
+                .unwrap_or_default()
+                .parse::<u16>()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid status code: {}", e)))?;
+            let description = parts.next().unwrap_or_default().trim().to_string();
+            Some(HeaderStatus { code, description })
+        };
+
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            if line.is_empty() {
+                break;
+            }
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap_or_default().trim();
+            let value = parts.next().unwrap_or_default().trim();
+            // `name` came from before the first `:` and `value` from a line
+            // already split on `\r\n`, so both are guaranteed valid here.
+            if !name.is_empty() {
+                headers
+                    .insert(name, value)
+                    .expect("name/value parsed off a single wire line are always valid");
+            }
+        }
+
+        Ok((headers, status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_then_parse_round_trips_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Nats-Stream", "orders").unwrap();
+        headers.insert("Nats-Sequence", "42").unwrap();
+
+        let (parsed, status) = HeaderMap::parse(&headers.render()).unwrap();
+
+        assert!(status.is_none());
+        assert_eq!(parsed.get("Nats-Stream"), Some("orders"));
+        assert_eq!(parsed.get("Nats-Sequence"), Some("42"));
+    }
+
+    #[test]
+    fn insert_appends_repeated_names_in_order() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Trace", "a").unwrap();
+        headers.insert("X-Trace", "b").unwrap();
+
+        let values: Vec<&str> = headers.get_all("X-Trace").collect();
+        assert_eq!(values, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn parse_reads_status_line() {
+        let block = b"NATS/1.0 503 No Responders\r\n\r\n";
+        let (headers, status) = HeaderMap::parse(block).unwrap();
+
+        let status = status.expect("status line should have been parsed");
+        assert_eq!(status.code, 503);
+        assert_eq!(status.description, "No Responders");
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn parse_rejects_missing_version_line() {
+        let block = b"Nats-Stream: orders\r\n\r\n";
+        assert!(HeaderMap::parse(block).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_stray_lf_instead_of_panicking() {
+        let block = b"NATS/1.0\r\nX-Foo: a\nInjected: yes\r\n\r\n";
+        assert!(HeaderMap::parse(block).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_stray_cr() {
+        let block = b"NATS/1.0\r\nX-Foo: a\rInjected: yes\r\n\r\n";
+        assert!(HeaderMap::parse(block).is_err());
+    }
+
+    #[test]
+    fn insert_rejects_colon_in_name() {
+        let mut headers = HeaderMap::new();
+        assert!(headers.insert("bad:name", "value").is_err());
+    }
+
+    #[test]
+    fn insert_rejects_crlf_in_value() {
+        let mut headers = HeaderMap::new();
+        assert!(headers.insert("X-Trace", "a\r\nInjected: yes").is_err());
+    }
+}
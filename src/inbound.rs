@@ -2,16 +2,26 @@ use std::{
     io::{self, BufRead, BufReader, Read},
     net::TcpStream,
     sync::{atomic::Ordering, Arc},
+    thread,
+    time::Duration,
 };
 
-use rand::{seq::SliceRandom, thread_rng};
+use crossbeam_channel::TrySendError;
+use rand::{seq::SliceRandom, thread_rng, Rng};
 
 use crate::{
+    header::{HeaderMap, HeaderStatus},
     inject_delay, inject_io_failure,
+    outbound::PongWaiter,
     parser::{parse_control_op, ControlOp, MsgArgs},
     Message, Server, ServerInfo, SharedState, SubscriptionState, TlsReader,
 };
 
+/// Default bound on the number of messages that may be queued for a single
+/// subscription before the read loop pauses to apply backpressure, matching
+/// the async port's `MAX_SUBSCRIPTION_QUEUE`.
+pub(crate) const MAX_SUBSCRIPTION_QUEUE: usize = 650;
+
 #[derive(Debug)]
 pub(crate) enum Reader {
     Tcp(BufReader<TcpStream>),
@@ -51,6 +61,12 @@ pub(crate) struct Inbound {
     pub(crate) configured_servers: Vec<Server>,
     pub(crate) learned_servers: Vec<Server>,
     pub(crate) shared_state: Arc<SharedState>,
+    // A message whose subscription channel was full the last time we tried
+    // to deliver it. While this is set, the read loop must not pull any
+    // more bytes off the socket until delivery succeeds (or the
+    // subscription goes away), so that a slow consumer applies backpressure
+    // instead of letting us buffer unboundedly many messages in memory.
+    congested: Option<(usize, Message)>,
 }
 
 impl Inbound {
@@ -75,9 +91,19 @@ impl Inbound {
 
     fn read_and_process_message(&mut self) -> io::Result<()> {
         inject_io_failure()?;
+
+        if let Some((sid, msg)) = self.congested.take() {
+            if !self.try_deliver(sid, msg)? {
+                // Still backed up: leave `self.congested` set and come back
+                // around without touching the socket.
+                return Ok(());
+            }
+        }
+
         let parsed_op = parse_control_op(&mut self.reader)?;
         match parsed_op {
             ControlOp::Msg(msg_args) => self.process_msg(msg_args)?,
+            ControlOp::HMsg(msg_args) => self.process_hmsg(msg_args)?,
             ControlOp::Ping => self.shared_state.outbound.send_pong()?,
             ControlOp::Pong => self.process_pong(),
             ControlOp::Info(new_info) => self.process_info(new_info),
@@ -101,8 +127,10 @@ impl Inbound {
         self.shared_state.outbound.transition_to_disconnected();
 
         // flush outstanding pongs
-        while let Some(s) = pongs.pop_front() {
-            s.send(false).unwrap();
+        while let Some(waiter) = pongs.pop_front() {
+            if let PongWaiter::Flush(sender) = waiter {
+                sender.send(false).unwrap();
+            }
         }
 
         // we only need to hold this mutex while setting the outbound buffer
@@ -126,6 +154,7 @@ impl Inbound {
 
         // loop through our known servers until we establish a connection, backing-off
         // more each time we cycle through the known set.
+        let mut cycle: u32 = 0;
         'outer: loop {
             if self.shared_state.shutting_down.load(Ordering::Acquire) {
                 log::warn!("ending reconnection attempt after detecting that the system shutdown flag is set");
@@ -200,6 +229,19 @@ impl Inbound {
                 );
                 return false;
             }
+
+            // None of the known servers accepted a connection this cycle;
+            // back off before trying the whole set again, so a server
+            // outage doesn't make us busy-loop through `try_connect`.
+            let base = self.shared_state.options.reconnect_strategy.base_delay(cycle);
+            cycle = cycle.saturating_add(1);
+            let jitter_bound = (base.as_secs_f64() / 2.0).max(f64::EPSILON);
+            let jitter = Duration::from_secs_f64(thread_rng().gen_range(0.0..jitter_bound));
+
+            if !self.sleep_respecting_shutdown(base + jitter) {
+                log::warn!("ending reconnection attempt after detecting that the system shutdown flag is set");
+                return false;
+            }
         }
 
         // reset all server connection attempts to 0
@@ -218,11 +260,32 @@ impl Inbound {
         true
     }
 
+    // Sleeps for `duration` in short slices so that a shutdown request can
+    // interrupt the wait promptly instead of only being noticed on the next
+    // reconnect cycle. Returns `false` if shutdown was observed.
+    fn sleep_respecting_shutdown(&self, duration: Duration) -> bool {
+        const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+        let mut remaining = duration;
+        while remaining > Duration::from_millis(0) {
+            if self.shared_state.shutting_down.load(Ordering::Acquire) {
+                return false;
+            }
+            let step = remaining.min(POLL_INTERVAL);
+            thread::sleep(step);
+            remaining -= step;
+        }
+
+        !self.shared_state.shutting_down.load(Ordering::Acquire)
+    }
+
     fn process_pong(&mut self) {
         inject_delay();
         let mut pongs = self.shared_state.pongs.lock();
-        if let Some(s) = pongs.pop_front() {
-            s.send(true).unwrap();
+        if let Some(waiter) = pongs.pop_front() {
+            if let PongWaiter::Flush(sender) = waiter {
+                sender.send(true).unwrap();
+            }
         }
     }
 
@@ -240,6 +303,8 @@ impl Inbound {
             subject: msg_args.subject,
             reply: msg_args.reply,
             data: Vec::with_capacity(msg_args.mlen as usize + CRLF_LEN as usize),
+            headers: None,
+            status: None,
             responder: None,
         };
 
@@ -257,11 +322,126 @@ impl Inbound {
         // truncate CRLF
         msg.data.truncate(msg_args.mlen as usize);
 
-        // Now lookup the subscription's channel.
-        let subs = self.shared_state.subs.read();
-        if let Some(SubscriptionState { sender, .. }) = subs.get(&msg_args.sid) {
-            sender.send(msg).unwrap();
+        self.try_deliver(msg_args.sid, msg)?;
+        Ok(())
+    }
+
+    // Like `process_msg`, but for the `HMSG` variant: the inbound buffer is
+    // `hdr_len` bytes of header block (a `NATS/1.0` version line, `Key:
+    // Value` lines, and a terminating blank line) followed by the payload.
+    //
+    // BLOCKED: a real server only emits `HMSG` for subjects we're
+    // subscribed to if our CONNECT advertised `"headers": true`, which
+    // isn't wired up yet -- see the note on `Outbound::send_pub_msg_with_headers`.
+    // Until that lands this method is unreachable against a live server.
+    fn process_hmsg(&mut self, msg_args: MsgArgs) -> io::Result<()> {
+        const CRLF_LEN: u32 = 2;
+
+        inject_io_failure()?;
+
+        let mut buf = Vec::with_capacity(msg_args.mlen as usize + CRLF_LEN as usize);
+
+        let reader = &mut self.reader;
+        // FIXME(dlc) - avoid copy if possible.
+        reader
+            .take(u64::from(msg_args.mlen + CRLF_LEN))
+            .read_to_end(&mut buf)?;
+
+        // truncate CRLF
+        buf.truncate(msg_args.mlen as usize);
+
+        // `hdr_len` comes straight off the wire: a malformed or hostile
+        // control line could claim a header block larger than the total
+        // payload we actually read, which would panic on the slicing below.
+        let hdr_len = msg_args.hdr_len as usize;
+        if hdr_len > buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "HMSG header length {} exceeds total payload length {}",
+                    hdr_len,
+                    buf.len()
+                ),
+            ));
         }
+        let (headers, status) = HeaderMap::parse(&buf[..hdr_len])?;
+
+        let mut msg = Message {
+            subject: msg_args.subject,
+            reply: msg_args.reply,
+            data: buf[hdr_len..].to_vec(),
+            headers: Some(headers),
+            // Surfaced as-is (e.g. `503 No Responders`) so request/response
+            // callers can tell a real empty reply from "nobody was
+            // listening" without inspecting the header map themselves.
+            status,
+            responder: None,
+        };
+
+        // Setup so we can send responses.
+        if msg.reply.is_some() {
+            msg.responder = Some(self.shared_state.clone());
+        }
+
+        self.try_deliver(msg_args.sid, msg)?;
         Ok(())
     }
+
+    // Attempts to hand `msg` off to the subscription identified by `sid`.
+    // Returns `Ok(true)` once the message has been delivered (or dropped,
+    // because the subscription no longer exists), and `Ok(false)` if the
+    // subscriber's queue is full, in which case `self.congested` is set so
+    // that `read_and_process_message` retries this same message instead of
+    // reading more frames off the wire.
+    //
+    // `shared_state.congested` mirrors whether we're currently parked here:
+    // chunk0-4's heartbeat thread checks it before deciding a silent
+    // connection is dead, since a subscriber that's merely slow -- not a
+    // broken socket -- is exactly what leaves PONGs unread while we're
+    // paused (we don't pull any more frames off the wire, heartbeat replies
+    // included, until delivery succeeds).
+    fn try_deliver(&mut self, sid: usize, msg: Message) -> io::Result<bool> {
+        let subs = self.shared_state.subs.read();
+        let sender = match subs.get(&sid) {
+            Some(SubscriptionState { sender, .. }) => sender.clone(),
+            None => {
+                self.shared_state.congested.store(false, Ordering::Release);
+                return Ok(true);
+            }
+        };
+        drop(subs);
+
+        match sender.try_send(msg) {
+            Ok(()) => {
+                self.shared_state.congested.store(false, Ordering::Release);
+                Ok(true)
+            }
+            Err(TrySendError::Full(msg)) => {
+                self.shared_state.congested.store(true, Ordering::Release);
+
+                // Give the consumer a brief window to drain before we come
+                // back around; `Subscription::next` notifies this same
+                // condvar whenever it pops a message off a congested queue.
+                let mut guard = self.shared_state.queue_drained.0.lock();
+                self.shared_state
+                    .queue_drained
+                    .1
+                    .wait_for(&mut guard, Duration::from_millis(50));
+                drop(guard);
+
+                self.congested = Some((sid, msg));
+                Ok(false)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.shared_state.congested.store(false, Ordering::Release);
+                log::debug!(
+                    "receiver for subscription {} was dropped; auto-unsubscribing",
+                    sid
+                );
+                self.shared_state.subs.write().remove(&sid);
+                self.shared_state.outbound.send_unsub(sid)?;
+                Ok(true)
+            }
+        }
+    }
 }
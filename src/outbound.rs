@@ -2,11 +2,25 @@ use std::{
     collections::HashMap,
     io::{self, BufWriter, Error, ErrorKind, Write},
     net::{Shutdown, TcpStream},
+    sync::{atomic::Ordering, mpsc, Arc},
+    thread,
 };
 
 use parking_lot::{Condvar, Mutex};
 
-use crate::{inject_delay, inject_io_failure, SubscriptionState, TlsWriter};
+use crate::{header::HeaderMap, inject_delay, inject_io_failure, SharedState, SubscriptionState, TlsWriter};
+
+/// An entry parked on `SharedState::pongs`, awaiting the next `PONG`.
+///
+/// Both `Connection::flush`/`flush_timeout` and the heartbeat thread park
+/// entries here; tagging them lets `Inbound::process_pong` wake a real
+/// flush waiter without mistaking a heartbeat sentinel for one (and vice
+/// versa when counting outstanding heartbeat pings).
+#[derive(Debug)]
+pub(crate) enum PongWaiter {
+    Flush(mpsc::Sender<bool>),
+    Heartbeat,
+}
 
 #[derive(Debug)]
 pub(crate) struct DisconnectWriter {
@@ -99,6 +113,17 @@ impl Writer {
         }
     }
 
+    // Bytes currently sitting in the write buffer, about to be handed to
+    // the OS on the next `flush`.
+    fn buffered_len(&self) -> usize {
+        match self {
+            Writer::Tcp(bw) => bw.buffer().len(),
+            Writer::Tls(bw) => bw.buffer().len(),
+            Writer::Disconnected(db) => db.len,
+            Writer::Closed => 0,
+        }
+    }
+
     fn shutdown(&mut self) -> io::Result<()> {
         match self {
             Writer::Tcp(bw) => {
@@ -140,14 +165,32 @@ pub(crate) struct Outbound {
     writer: Mutex<Writer>,
     updated: Condvar,
     reconnect_buffer_size: usize,
+    // Tracks how many payload bytes have been handed to `send_pub_msg` /
+    // `send_pub_msg_with_headers` / `send_response` but not yet flushed to
+    // the socket, so a fast publisher can't grow the `BufWriter` without
+    // bound ahead of a slow network.
+    pending_bytes: Mutex<usize>,
+    pending_bytes_limit: usize,
+    // When the limit above is reached: block the publisher on `updated`
+    // until the flusher makes room (`true`), or return `WouldBlock`
+    // immediately so the caller can shed the write itself (`false`).
+    block_on_backpressure: bool,
 }
 
 impl Outbound {
-    pub(crate) fn new(writer: Writer, reconnect_buffer_size: usize) -> Outbound {
+    pub(crate) fn new(
+        writer: Writer,
+        reconnect_buffer_size: usize,
+        pending_bytes_limit: usize,
+        block_on_backpressure: bool,
+    ) -> Outbound {
         Outbound {
             writer: Mutex::new(writer),
             updated: Condvar::new(),
             reconnect_buffer_size,
+            pending_bytes: Mutex::new(0),
+            pending_bytes_limit,
+            block_on_backpressure,
         }
     }
 
@@ -164,14 +207,90 @@ impl Outbound {
                 return;
             }
 
-            if let Err(error) = writer.flush() {
-                log::error!("Outbound thread failed to flush: {:?}", error);
+            // Capture how many bytes we're about to drain *before* flushing,
+            // while still holding `writer`, so the amount we later credit
+            // back to `pending_bytes` can never race with a publisher that
+            // reserves (and writes) more bytes in between.
+            let about_to_flush = writer.buffered_len();
+
+            match writer.flush() {
+                Ok(()) => {
+                    let mut pending = self.pending_bytes.lock();
+                    *pending = pending.saturating_sub(about_to_flush);
+                    drop(pending);
+                    self.updated.notify_all();
+                }
+                Err(error) => {
+                    log::error!("Outbound thread failed to flush: {:?}", error);
 
-                let _unchecked = writer.shutdown();
+                    let _unchecked = writer.shutdown();
 
-                // wait on the Condvar here until the inbound thread
-                // replaces our buffer
-                self.updated.wait(&mut writer);
+                    // wait on the Condvar here until the inbound thread
+                    // replaces our buffer
+                    self.updated.wait(&mut writer);
+                }
+            }
+        }
+    }
+
+    // Reserves `payload_len` bytes of headroom in the pending publish
+    // buffer and then runs `f` with the writer locked, all under a single
+    // acquisition of `self.writer`. Reserving and writing atomically (rather
+    // than reserving under a separate `pending_bytes` lock first) is what
+    // keeps `pending_bytes` an accurate bound: nothing else can sneak a
+    // reservation in, or have the flusher credit bytes back, between the
+    // moment we decide there's room and the moment we actually buffer the
+    // write.
+    //
+    // Blocks or returns `WouldBlock` per `block_on_backpressure` once
+    // `pending_bytes_limit` would be exceeded. A publish that would exceed
+    // the limit on its own is still allowed through so long as nothing else
+    // is currently pending, to avoid deadlocking on oversized single
+    // messages.
+    fn with_writer_limited<F>(&self, payload_len: usize, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Writer) -> io::Result<()>,
+    {
+        inject_delay();
+        let mut writer = self.writer.lock();
+
+        loop {
+            let mut pending = self.pending_bytes.lock();
+            // Bytes no longer in the writer's buffer have already gone out
+            // (flushed in place by a PING/PONG/UNSUB, or written through by
+            // `BufWriter` once full) without passing `flush_loop`, so they
+            // no longer count as pending.
+            *pending = (*pending).min(writer.buffered_len());
+            if *pending == 0 || *pending + payload_len <= self.pending_bytes_limit {
+                *pending += payload_len;
+                break;
+            }
+            drop(pending);
+
+            if !self.block_on_backpressure {
+                return Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    "the pending publish buffer is full",
+                ));
+            }
+
+            // `wait` releases `writer` while parked, which lets the flusher
+            // drain the buffer and credit bytes back; the lock is held again
+            // before we re-check.
+            self.updated.wait(&mut writer);
+        }
+
+        match (f)(&mut *writer) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let mut pending = self.pending_bytes.lock();
+                *pending = pending.saturating_sub(payload_len);
+                drop(pending);
+
+                // Shutdown socket to ensure we propagate the error to the Inbound reader.
+                let _unchecked = writer.shutdown();
+                writer.transition_to_disconnected(self.reconnect_buffer_size);
+                Err(e)
             }
         }
     }
@@ -182,6 +301,20 @@ impl Outbound {
         writer.transition_to_disconnected(self.reconnect_buffer_size);
     }
 
+    // Forces the socket closed without marking the connection permanently
+    // `Closed`, so that the blocked read in `Inbound::read_and_process_message`
+    // observes an I/O error and falls into the ordinary reconnect path --
+    // the same path a dead-socket write error would trigger. Used by the
+    // heartbeat thread once too many pings have gone unanswered.
+    pub(crate) fn reset_for_stale_connection(&self) {
+        inject_delay();
+        let mut writer = self.writer.lock();
+        let _unchecked = writer.shutdown();
+        writer.transition_to_disconnected(self.reconnect_buffer_size);
+        drop(writer);
+        self.updated.notify_all();
+    }
+
     pub(crate) fn close(&self) {
         inject_delay();
         let mut writer = self.writer.lock();
@@ -225,6 +358,15 @@ impl Outbound {
                 let _unchecked = new_writer.shutdown();
                 return Err(error);
             }
+
+            // The disconnect buffer was written and flushed straight to the
+            // new socket, bypassing `flush_loop`, so credit these bytes back
+            // here; otherwise everything published while disconnected would
+            // keep counting against `pending_bytes_limit` until the next
+            // limited publish re-checks the buffer.
+            let mut pending = self.pending_bytes.lock();
+            *pending = pending.saturating_sub(db.len);
+            drop(pending);
         }
         *writer = new_writer;
         drop(writer);
@@ -292,7 +434,7 @@ impl Outbound {
         reply: Option<&str>,
         msgb: &[u8],
     ) -> io::Result<()> {
-        self.with_writer(|writer| {
+        self.with_writer_limited(msgb.len(), |writer| {
             if let Some(reply) = reply {
                 write!(writer, "PUB {} {} {}\r\n", subj, reply, msgb.len())?;
             } else {
@@ -305,6 +447,39 @@ impl Outbound {
         })
     }
 
+    // BLOCKED: this method and the `HMsg`/`process_hmsg` read path added
+    // alongside it are NOT exercisable against a real NATS server yet. The
+    // server only ever emits `HMSG` instead of `MSG` for subjects we're
+    // subscribed to if our CONNECT advertised `"headers": true`, and that
+    // flag is set on the CONNECT line built in `Server::try_connect`
+    // (server.rs). `server.rs` doesn't exist in this change's source tree,
+    // so that wiring could not be done here -- do not treat headers support
+    // as complete or mergeable until it lands. Whoever owns server.rs needs
+    // to flip `ConnectInfo::headers` to `true` there first.
+    pub(crate) fn send_pub_msg_with_headers(
+        &self,
+        subj: &str,
+        reply: Option<&str>,
+        headers: &HeaderMap,
+        msgb: &[u8],
+    ) -> io::Result<()> {
+        let header_block = headers.render();
+        self.with_writer_limited(header_block.len() + msgb.len(), |writer| {
+            let hdr_len = header_block.len();
+            let total_len = hdr_len + msgb.len();
+            if let Some(reply) = reply {
+                write!(writer, "HPUB {} {} {} {}\r\n", subj, reply, hdr_len, total_len)?;
+            } else {
+                write!(writer, "HPUB {} {} {}\r\n", subj, hdr_len, total_len)?;
+            }
+            writer.write_all(&header_block)?;
+            writer.write_all(msgb)?;
+            writer.write_all(b"\r\n")?;
+            self.updated.notify_all();
+            Ok(())
+        })
+    }
+
     pub(crate) fn send_sub_msg(
         &self,
         subject: &str,
@@ -338,7 +513,7 @@ impl Outbound {
     }
 
     pub(crate) fn send_response(&self, subj: &str, msgb: &[u8]) -> io::Result<()> {
-        self.with_writer(|writer| {
+        self.with_writer_limited(msgb.len(), |writer| {
             write!(writer, "PUB {} {}\r\n", subj, msgb.len())?;
             writer.write_all(msgb)?;
             writer.write_all(b"\r\n")?;
@@ -347,3 +522,52 @@ impl Outbound {
         })
     }
 }
+
+// Runs for the lifetime of the connection, periodically sending a PING and
+// parking a `PongWaiter::Heartbeat` sentinel on `shared_state.pongs`. If
+// `max_pings_out` consecutive heartbeats go unanswered, the connection is
+// presumed dead (e.g. a half-open socket after a NAT timeout) and we force
+// it closed so the read loop reconnects, rather than waiting for a write to
+// eventually fail.
+pub(crate) fn heartbeat_loop(shared_state: Arc<SharedState>) {
+    loop {
+        thread::sleep(shared_state.options.ping_interval);
+
+        if shared_state.shutting_down.load(Ordering::Acquire) {
+            return;
+        }
+
+        if shared_state.congested.load(Ordering::Acquire) {
+            // Inbound::try_deliver has parked a message on a full
+            // subscription queue and, per chunk0-2, has stopped pulling any
+            // more frames -- including PONGs -- off the socket until it
+            // drains. That silence is backpressure working as intended, not
+            // a dead connection, so don't let it build toward a forced
+            // reconnect here.
+            log::debug!("skipping heartbeat check while a subscriber is congested");
+            continue;
+        }
+
+        let outstanding = shared_state
+            .pongs
+            .lock()
+            .iter()
+            .filter(|waiter| matches!(waiter, PongWaiter::Heartbeat))
+            .count();
+
+        if outstanding >= shared_state.options.max_pings_out {
+            log::warn!(
+                "connection appears stale after {} unanswered heartbeat pings; forcing reconnect",
+                outstanding
+            );
+            shared_state.outbound.reset_for_stale_connection();
+            continue;
+        }
+
+        shared_state.pongs.lock().push_back(PongWaiter::Heartbeat);
+
+        if let Err(e) = shared_state.outbound.send_ping() {
+            log::warn!("failed to send heartbeat ping: {:?}", e);
+        }
+    }
+}
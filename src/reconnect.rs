@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+/// Controls how long `Inbound::reconnect` waits between cycles through the
+/// known server set once every server in a cycle has refused a connection.
+#[derive(Debug, Clone, Copy)]
+pub enum ReconnectStrategy {
+    /// Wait the same fixed amount of time before every retry cycle.
+    FixedInterval(Duration),
+    /// Back off exponentially between retry cycles, capped at `max`.
+    ExponentialBackoff {
+        base: Duration,
+        max: Duration,
+        factor: f64,
+    },
+}
+
+impl Default for ReconnectStrategy {
+    fn default() -> ReconnectStrategy {
+        ReconnectStrategy::ExponentialBackoff {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(8),
+            factor: 2.0,
+        }
+    }
+}
+
+impl ReconnectStrategy {
+    // The base delay for the given cycle `attempt` (0-indexed), before
+    // jitter is applied. Jitter is added by the caller, which already has
+    // a `thread_rng` in scope.
+    pub(crate) fn base_delay(&self, attempt: u32) -> Duration {
+        match *self {
+            ReconnectStrategy::FixedInterval(interval) => interval,
+            ReconnectStrategy::ExponentialBackoff { base, max, factor } => {
+                let scaled = base.as_secs_f64() * factor.powi(attempt as i32);
+                Duration::from_secs_f64(scaled.min(max.as_secs_f64()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_interval_ignores_attempt() {
+        let strategy = ReconnectStrategy::FixedInterval(Duration::from_millis(250));
+        assert_eq!(strategy.base_delay(0), Duration::from_millis(250));
+        assert_eq!(strategy.base_delay(10), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_each_attempt() {
+        let strategy = ReconnectStrategy::ExponentialBackoff {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(8),
+            factor: 2.0,
+        };
+
+        assert_eq!(strategy.base_delay(0), Duration::from_millis(100));
+        assert_eq!(strategy.base_delay(1), Duration::from_millis(200));
+        assert_eq!(strategy.base_delay(2), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn exponential_backoff_caps_at_max() {
+        let strategy = ReconnectStrategy::ExponentialBackoff {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(8),
+            factor: 2.0,
+        };
+
+        assert_eq!(strategy.base_delay(20), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn default_strategy_is_exponential_backoff() {
+        match ReconnectStrategy::default() {
+            ReconnectStrategy::ExponentialBackoff { base, max, factor } => {
+                assert_eq!(base, Duration::from_millis(100));
+                assert_eq!(max, Duration::from_secs(8));
+                assert_eq!(factor, 2.0);
+            }
+            ReconnectStrategy::FixedInterval(_) => panic!("default should be exponential backoff"),
+        }
+    }
+}